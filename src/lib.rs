@@ -1,4 +1,5 @@
 use ext_php_rs::binary::Binary;
+use ext_php_rs::convert::IntoZval;
 use ext_php_rs::prelude::*;
 use ext_php_rs::types::Zval;
 use ext_php_rs::{exception::PhpException, zend::ce};
@@ -72,7 +73,122 @@ impl PHPValue {
 #[php_class]
 #[extends(ce::exception())]
 #[derive(Default)]
-pub struct V8JsScriptException;
+#[allow(non_snake_case)]
+pub struct V8JsScriptException {
+    #[prop]
+    JsV8_Message: String,
+    #[prop]
+    JsV8_Line: i64,
+    #[prop]
+    JsV8_SourceLine: String,
+    #[prop]
+    JsV8_Trace: String,
+}
+
+#[php_class]
+#[extends(ce::exception())]
+#[derive(Default)]
+pub struct V8JsTimeLimitException;
+
+#[php_class]
+#[extends(ce::exception())]
+#[derive(Default)]
+pub struct V8JsMemoryLimitException;
+
+/// Why a call to `JSRuntime::execute_string` failed. A plain JS throw or
+/// compile error carries its `JSError` detail; the watchdog paths terminate
+/// execution and surface as their own PHP exception types instead.
+#[derive(Debug, Clone)]
+pub enum ExecutionError {
+    Script(JSError),
+    TimeLimit,
+    MemoryLimit,
+}
+
+impl From<ExecutionError> for PhpException {
+    fn from(error: ExecutionError) -> Self {
+        match error {
+            ExecutionError::Script(error) => error.throw(),
+            ExecutionError::TimeLimit => PhpException::new(
+                String::from("Script timeout exceeded"),
+                0,
+                V8JsTimeLimitException::get_metadata().ce(),
+            ),
+            ExecutionError::MemoryLimit => PhpException::new(
+                String::from("Script memory limit exceeded"),
+                0,
+                V8JsMemoryLimitException::get_metadata().ce(),
+            ),
+        }
+    }
+}
+
+/// Detail extracted from a `v8::TryCatch` when a script fails to compile or
+/// throws. Produced by `JSRuntime::execute_string` and mapped onto a
+/// `V8JsScriptException` before it is handed back to PHP.
+#[derive(Debug, Clone)]
+pub struct JSError {
+    pub message: String,
+    pub line: i64,
+    pub source_line: String,
+    pub trace: String,
+}
+
+impl JSError {
+    /// Extract the message, location and `.stack` from a caught `TryCatch`.
+    pub fn from_try_catch(
+        try_catch: &mut v8::TryCatch<v8::HandleScope>,
+        exception: v8::Local<v8::Value>,
+    ) -> Self {
+        let mut error = JSError::from_value(try_catch, exception);
+        if let Some(message) = try_catch.message() {
+            let scope: &mut v8::HandleScope = try_catch;
+            error.message = message.get(scope).to_rust_string_lossy(scope);
+            error.line = message.get_line_number(scope).unwrap_or(0) as i64;
+            if let Some(source_line) = message.get_source_line(scope) {
+                error.source_line = source_line.to_rust_string_lossy(scope);
+            }
+        }
+        error
+    }
+
+    /// Build the error detail from a thrown value (e.g. a promise rejection),
+    /// reading its `.stack` when the value is an `Error` object.
+    pub fn from_value(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> Self {
+        let message = value.to_rust_string_lossy(scope);
+        let mut trace = String::new();
+        if let Ok(object) = v8::Local::<v8::Object>::try_from(value) {
+            let key = v8::String::new(scope, "stack").unwrap();
+            if let Some(stack) = object.get(scope, key.into()) {
+                if !stack.is_null_or_undefined() {
+                    trace = stack.to_rust_string_lossy(scope);
+                }
+            }
+        }
+        JSError {
+            message,
+            line: 0,
+            source_line: String::new(),
+            trace,
+        }
+    }
+
+    /// Turn the caught JS detail into a `V8JsScriptException` carrying the
+    /// named properties (`JsV8_Message`, `JsV8_Line`, `JsV8_SourceLine`,
+    /// `JsV8_Trace`) the original v8js extension exposed, then throw it.
+    pub fn throw(self) -> PhpException {
+        let object = V8JsScriptException {
+            JsV8_Message: self.message.clone(),
+            JsV8_Line: self.line,
+            JsV8_SourceLine: self.source_line,
+            JsV8_Trace: self.trace,
+        };
+        match object.into_zval(false) {
+            Ok(zval) => PhpException::from(zval),
+            Err(_) => PhpException::new(self.message, 0, V8JsScriptException::get_metadata().ce()),
+        }
+    }
+}
 
 pub fn js_value_from_zval<'a>(
     scope: &mut v8::HandleScope<'a>,
@@ -96,6 +212,9 @@ pub fn js_value_from_zval<'a>(
     if zval.is_null() {
         return v8::null(scope).into();
     }
+    if zval.is_object() {
+        return js_object_from_zval(scope, zval);
+    }
     if zval.is_array() {
         let zend_array = zval.array().unwrap();
         let mut values: Vec<v8::Local<'_, v8::Value>> = Vec::new();
@@ -124,6 +243,170 @@ pub fn js_value_from_zval<'a>(
     v8::null(scope).into()
 }
 
+/// Wrap a PHP object as a live JS object. Rather than flattening it into a
+/// plain data object, we hang a named-property interceptor off an
+/// `ObjectTemplate` and keep the originating object handle in an internal
+/// field so property reads and method calls can reach back into PHP on demand.
+///
+/// Wrapping the same PHP object twice (e.g. a method returning `$this`, or a
+/// property read inside a loop) reuses the cached wrapper for its Zend object
+/// handle rather than boxing another copy: `owned_objects` is otherwise never
+/// pruned and lives for the isolate's whole lifetime, so without the cache a
+/// script that repeatedly re-wraps the same object would leak one `Zval` per
+/// call.
+fn js_object_from_zval<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    zval: &'_ Zval,
+) -> v8::Local<'a, v8::Value> {
+    let handle = zval.object().map(|object| object.handle());
+
+    if let Some(handle) = handle {
+        let cached = {
+            let isolate: &mut v8::Isolate = scope.as_mut();
+            let state = JSRuntime::state(isolate);
+            let state = state.borrow();
+            state.wrapped_objects.get(&handle).cloned()
+        };
+        if let Some(global) = cached {
+            return v8::Local::new(scope, global).into();
+        }
+    }
+
+    let template = v8::ObjectTemplate::new(scope);
+    template.set_internal_field_count(1);
+    let config =
+        v8::NamedPropertyHandlerConfiguration::new().getter(php_object_property_getter);
+    template.set_named_property_handler(config);
+
+    let object = template.new_instance(scope).unwrap();
+
+    // Own the PHP object in the runtime state so it lives as long as the
+    // isolate (and is dropped when the runtime is), then stash a raw pointer
+    // to the boxed `Zval` in the wrapper's internal field. The box keeps the
+    // address stable even as the owning vector grows.
+    let pointer = {
+        let isolate: &mut v8::Isolate = scope.as_mut();
+        let state = JSRuntime::state(isolate);
+        let mut state = state.borrow_mut();
+        state.owned_objects.push(Box::new(zval.shallow_clone()));
+        &**state.owned_objects.last().unwrap() as *const Zval
+    };
+    let external = v8::External::new(scope, pointer as *mut std::ffi::c_void);
+    object.set_internal_field(0, external.into());
+
+    if let Some(handle) = handle {
+        let global = v8::Global::new(scope, object);
+        let isolate: &mut v8::Isolate = scope.as_mut();
+        let state = JSRuntime::state(isolate);
+        state.borrow_mut().wrapped_objects.insert(handle, global);
+    }
+
+    object.into()
+}
+
+/// Raw pointer to the PHP object a wrapper was built around, read from its
+/// internal field. The pointer is only valid for the isolate's lifetime and
+/// must be dereferenced in a narrow `unsafe` block by the caller.
+fn wrapped_php_object(
+    scope: &mut v8::HandleScope,
+    object: v8::Local<v8::Object>,
+) -> Option<*const Zval> {
+    let field = object.get_internal_field(scope, 0)?;
+    let external = v8::Local::<v8::External>::try_from(field).ok()?;
+    Some(external.value() as *const Zval)
+}
+
+/// Return the cached trampoline for `name`, building and caching it on first
+/// use so `obj.method === obj.method` holds.
+fn cached_method<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    name: &str,
+) -> v8::Local<'a, v8::Function> {
+    let state = {
+        let isolate: &mut v8::Isolate = scope.as_mut();
+        JSRuntime::state(isolate)
+    };
+    if let Some(function) = state.borrow().wrapper_methods.get(name) {
+        return v8::Local::new(scope, function);
+    }
+    let data = v8::String::new(scope, name).unwrap();
+    let function = v8::FunctionBuilder::<v8::Function>::new(php_method_callback)
+        .data(data.into())
+        .build(scope)
+        .unwrap();
+    state
+        .borrow_mut()
+        .wrapper_methods
+        .insert(name.to_string(), v8::Global::new(scope, function));
+    function
+}
+
+fn php_object_property_getter(
+    scope: &mut v8::HandleScope,
+    key: v8::Local<v8::Name>,
+    args: v8::PropertyCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let pointer = match wrapped_php_object(scope, args.this()) {
+        Some(pointer) => pointer,
+        None => return,
+    };
+    // SAFETY: the pointer refers to a `Zval` owned by the runtime state for
+    // the isolate's lifetime (see `js_object_from_zval`).
+    let object = match unsafe { &*pointer }.object() {
+        Some(object) => object,
+        None => return,
+    };
+    let name = key.to_rust_string_lossy(scope);
+
+    // A public property is read and converted by value.
+    if let Ok(value) = object.get_property::<&Zval>(name.as_str()) {
+        rv.set(js_value_from_zval(scope, value));
+        return;
+    }
+
+    // A public method resolves to a cached trampoline; any other name is left
+    // undefined so wrappers don't masquerade as thenable/callable.
+    if object.has_method(name.as_str()) {
+        let function = cached_method(scope, name.as_str());
+        rv.set(function.into());
+    }
+}
+
+fn php_method_callback(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let this = match v8::Local::<v8::Object>::try_from(args.this().into()) {
+        Ok(this) => this,
+        Err(_) => return,
+    };
+    let pointer = match wrapped_php_object(scope, this) {
+        Some(pointer) => pointer,
+        None => return,
+    };
+    // SAFETY: see `php_object_property_getter`.
+    let object = match unsafe { &*pointer }.object() {
+        Some(object) => object,
+        None => return,
+    };
+    let name = args.data().unwrap().to_rust_string_lossy(scope);
+
+    let mut php_args: Vec<PHPValue> = Vec::new();
+    for index in 0..args.length() {
+        php_args.push(PHPValue::from(args.get(index), scope));
+    }
+    let php_arg_refs: Vec<&dyn ext_php_rs::convert::IntoZvalDyn> = php_args
+        .iter()
+        .map(|arg| arg as &dyn ext_php_rs::convert::IntoZvalDyn)
+        .collect();
+
+    if let Ok(result) = object.try_call_method(name.as_str(), php_arg_refs) {
+        rv.set(js_value_from_zval(scope, &result));
+    }
+}
+
 #[php_class]
 pub struct V8Js {
     global_name: String,
@@ -159,21 +442,15 @@ impl V8Js {
         runtime.add_global_function("print", php_callback_var_dump);
         runtime.add_global_function("exit", php_callback_exit);
         runtime.add_global_function("sleep", php_callback_sleep);
+        runtime.add_global_function("require", php_callback_require);
         V8Js {
             runtime,
             global_name,
         }
     }
-    pub fn set_module_loader(&mut self, _callable: &Zval) {
-        // let mut loader = self
-        //     .runtime
-        //     .isolate
-        //     .get_slot::<Rc<RefCell<ModuleLoader>>>()
-        //     .unwrap()
-        //     .borrow_mut();
-        // let callable = callable.shallow_clone();
-        // loader.callback = Some(callable);
-        // self.commonjs_module_loader = Some(callable)
+    pub fn set_module_loader(&mut self, callable: &Zval) {
+        let callable = callable.shallow_clone();
+        self.runtime.set_module_loader(callable);
     }
 
     pub fn execute_string(
@@ -183,6 +460,7 @@ impl V8Js {
         _flags: Option<String>,
         time_limit: Option<u64>,
         memory_limit: Option<u64>,
+        await_promise: Option<bool>,
     ) -> Result<PHPValue, PhpException> {
         let result = self.runtime.execute_string(
             string.as_str(),
@@ -190,6 +468,7 @@ impl V8Js {
             _flags,
             time_limit,
             memory_limit,
+            await_promise.unwrap_or(false),
         );
 
         match result {
@@ -203,7 +482,7 @@ impl V8Js {
                     None => Ok(PHPValue::None),
                 }
             }
-            _ => Err(PhpException::default(String::from("Exception"))),
+            Err(error) => Err(error.into()),
         }
     }
 
@@ -236,6 +515,106 @@ impl V8Js {
         }
     }
 
+    pub fn serialize(&mut self, value: &Zval) -> Result<Zval, PhpException> {
+        let mut scope = self.runtime.handle_scope();
+        let try_catch = &mut v8::TryCatch::new(&mut scope);
+        let context = try_catch.get_current_context();
+        let js_value = js_value_from_zval(try_catch, value);
+
+        let mut serializer = v8::ValueSerializer::new(try_catch, Box::new(StructuredClone));
+        serializer.write_header();
+        // `write_value` threads every object through the serializer's object-ID
+        // table, so shared and cyclic references are recorded as back-pointers
+        // rather than re-walked — no infinite recursion, no lost identity.
+        match serializer.write_value(context, js_value) {
+            Some(true) => {
+                let bytes = serializer.release();
+                let mut zval = Zval::new();
+                zval.set_binary(bytes);
+                Ok(zval)
+            }
+            _ => {
+                // The data-clone delegate threw for an unserialisable value.
+                // Drop the serializer (releasing the scope borrow), clear the
+                // pending exception so it can't corrupt the next
+                // `execute_string`, and surface the failure to PHP instead of
+                // returning a truncated, header-only blob.
+                drop(serializer);
+                let message = try_catch
+                    .exception()
+                    .map(|exception| exception.to_rust_string_lossy(try_catch))
+                    .unwrap_or_else(|| String::from("Value could not be serialized"));
+                try_catch.reset();
+                Err(PhpException::default(message))
+            }
+        }
+    }
+
+    pub fn deserialize(&mut self, blob: Binary<u8>) -> Result<PHPValue, PhpException> {
+        let mut scope = self.runtime.handle_scope();
+        let try_catch = &mut v8::TryCatch::new(&mut scope);
+        let context = try_catch.get_current_context();
+        let bytes = blob.as_slice();
+
+        let mut deserializer =
+            v8::ValueDeserializer::new(try_catch, Box::new(StructuredClone), bytes);
+        // A truncated/corrupted blob or an unsupported version throws on the
+        // isolate rather than just returning `None`; same handling as
+        // `serialize` above so the pending exception can't outlive this call
+        // and corrupt the next `execute_string`/`serialize`.
+        if deserializer.read_header(context).is_none() {
+            drop(deserializer);
+            let message = try_catch
+                .exception()
+                .map(|exception| exception.to_rust_string_lossy(try_catch))
+                .unwrap_or_else(|| String::from("Value could not be deserialized"));
+            try_catch.reset();
+            return Err(PhpException::default(message));
+        }
+        match deserializer.read_value(context) {
+            Some(value) => Ok(PHPValue::from(value, try_catch)),
+            None => {
+                drop(deserializer);
+                let message = try_catch
+                    .exception()
+                    .map(|exception| exception.to_rust_string_lossy(try_catch))
+                    .unwrap_or_else(|| String::from("Value could not be deserialized"));
+                try_catch.reset();
+                Err(PhpException::default(message))
+            }
+        }
+    }
+
+    pub fn get_heap_statistics(&mut self) -> PHPValue {
+        let mut scope = self.runtime.handle_scope();
+        let isolate: &mut v8::Isolate = scope.as_mut();
+        let mut stats = v8::HeapStatistics::default();
+        isolate.get_heap_statistics(&mut stats);
+
+        let mut map: HashMap<String, PHPValue> = HashMap::new();
+        map.insert(
+            String::from("total_heap_size"),
+            PHPValue::Integer(stats.total_heap_size() as i64),
+        );
+        map.insert(
+            String::from("used_heap_size"),
+            PHPValue::Integer(stats.used_heap_size() as i64),
+        );
+        map.insert(
+            String::from("heap_size_limit"),
+            PHPValue::Integer(stats.heap_size_limit() as i64),
+        );
+        map.insert(
+            String::from("external_memory"),
+            PHPValue::Integer(stats.external_memory() as i64),
+        );
+        map.insert(
+            String::from("number_of_native_contexts"),
+            PHPValue::Integer(stats.number_of_native_contexts() as i64),
+        );
+        PHPValue::Object(map)
+    }
+
     pub fn create_snapshot(source: String) -> Option<Zval> {
         let snapshot = JSRuntime::create_snapshot(source)?;
         let mut zval = Zval::new();
@@ -243,6 +622,24 @@ impl V8Js {
         Some(zval)
     }
 }
+/// Delegate backing `V8Js::serialize`/`deserialize`. We rely entirely on the
+/// default structured-clone behaviour; the only hook we must provide is the
+/// data-clone error path, which surfaces unsupported values as a JS exception.
+struct StructuredClone;
+
+impl v8::ValueSerializerImpl for StructuredClone {
+    fn throw_data_clone_error<'s>(
+        &self,
+        scope: &mut v8::HandleScope<'s>,
+        message: v8::Local<'s, v8::String>,
+    ) {
+        let error = v8::Exception::error(scope, message);
+        scope.throw_exception(error);
+    }
+}
+
+impl v8::ValueDeserializerImpl for StructuredClone {}
+
 #[derive(Debug)]
 struct StartupData {
     data: *const char,
@@ -285,6 +682,22 @@ pub fn php_callback(
     rv.set(return_value_js)
 }
 
+pub fn php_callback_require(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let identifier = args.get(0).to_rust_string_lossy(scope);
+    // This is the top-level `require` only; it has no parent module, so
+    // relative ids resolve against the entry root. Modules reached from here
+    // receive their own path-bound `require` (see `JSRuntime::require`), which
+    // is what lets their relative requires resolve correctly.
+    match JSRuntime::require(scope, identifier.as_str(), None) {
+        Some(exports) => rv.set(v8::Local::new(scope, exports)),
+        None => {}
+    }
+}
+
 pub fn php_callback_sleep(
     scope: &mut v8::HandleScope,
     args: v8::FunctionCallbackArguments,
@@ -330,6 +743,13 @@ pub fn php_callback_exit(
         return ();
     }
 
+    // Flag this as a cooperative exit so the terminate below isn't later
+    // mistaken for a watchdog timeout.
+    {
+        let isolate: &mut v8::Isolate = scope.as_mut();
+        JSRuntime::state(isolate).borrow_mut().exited = true;
+    }
+
     // There's no way to immediately terminate execution in V8 so
     // we have to spin it's wheels with an inf. loop until it terminates.
     let script;
@@ -404,4 +824,39 @@ mod integration {
     fn php_bridge() {
         run_php("php_bridge.php");
     }
+
+    #[test]
+    fn commonjs() {
+        run_php("commonjs.php");
+    }
+
+    #[test]
+    fn await_promise() {
+        run_php("await.php");
+    }
+
+    #[test]
+    fn serialize() {
+        run_php("serialize.php");
+    }
+
+    #[test]
+    fn time_limit() {
+        run_php("time_limit.php");
+    }
+
+    #[test]
+    fn memory_limit() {
+        run_php("memory_limit.php");
+    }
+
+    #[test]
+    fn object_wrapping() {
+        run_php("object_wrapping.php");
+    }
+
+    #[test]
+    fn heap_statistics() {
+        run_php("heap_statistics.php");
+    }
 }
@@ -0,0 +1,575 @@
+use ext_php_rs::types::Zval;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use crate::{ExecutionError, JSError};
+
+static PLATFORM: Once = Once::new();
+
+type GlobalFunction =
+    fn(&mut v8::HandleScope, v8::FunctionCallbackArguments, v8::ReturnValue);
+
+/// Per-isolate state stashed in an isolate slot as `Rc<RefCell<State>>`, so the
+/// `php_callback`-style trampolines can reach the stored PHP callables, the
+/// module loader and the CommonJS module cache while a script is running.
+pub struct State {
+    /// PHP callables registered through `V8Js::__set`, keyed by property name.
+    pub callbacks: HashMap<String, Zval>,
+    /// The `set_module_loader` callback used to fetch module source text.
+    pub module_loader: Option<Zval>,
+    /// `module.exports` of every resolved module, keyed by normalised path.
+    pub module_cache: HashMap<String, v8::Global<v8::Value>>,
+    /// PHP objects handed to JS as live wrappers; owned here so the raw
+    /// pointers held in their V8 internal fields stay valid for the isolate's
+    /// lifetime and are dropped when the runtime is. Entries are never
+    /// pruned, but `wrapped_objects` below keeps this from growing once per
+    /// wrap: only the first time a given PHP object is wrapped does it push
+    /// a new entry here.
+    pub owned_objects: Vec<Box<Zval>>,
+    /// Wrapper instances already built for a PHP object, keyed by its Zend
+    /// object handle, so wrapping the same object again (e.g. a method
+    /// called repeatedly in a loop) returns the cached wrapper instead of
+    /// boxing another copy into `owned_objects` every time.
+    pub wrapped_objects: HashMap<u32, v8::Global<v8::Object>>,
+    /// Cached method trampolines keyed by method name, so repeated reads of
+    /// the same method off a wrapper return an identical function.
+    pub wrapper_methods: HashMap<String, v8::Global<v8::Function>>,
+    /// Set by the `exit()` global so a cooperative termination can be told
+    /// apart from a watchdog-driven one. Reset at the start of each run.
+    pub exited: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            callbacks: HashMap::new(),
+            module_loader: None,
+            module_cache: HashMap::new(),
+            owned_objects: Vec::new(),
+            wrapped_objects: HashMap::new(),
+            wrapper_methods: HashMap::new(),
+            exited: false,
+        }
+    }
+}
+
+pub struct JSRuntime {
+    isolate: v8::OwnedIsolate,
+    context: v8::Global<v8::Context>,
+}
+
+impl JSRuntime {
+    pub fn new(snapshot_blob: Option<Vec<u8>>) -> Self {
+        PLATFORM.call_once(|| {
+            let platform = v8::new_default_platform(0, false).make_shared();
+            v8::V8::initialize_platform(platform);
+            v8::V8::initialize();
+        });
+
+        let mut params = v8::CreateParams::default();
+        if let Some(blob) = snapshot_blob {
+            params = params.snapshot_blob(blob);
+        }
+        let mut isolate = v8::Isolate::new(params);
+        isolate.set_slot(Rc::new(RefCell::new(State::new())));
+
+        let context = {
+            let scope = &mut v8::HandleScope::new(&mut isolate);
+            let context = v8::Context::new(scope);
+            v8::Global::new(scope, context)
+        };
+
+        JSRuntime { isolate, context }
+    }
+
+    /// A handle scope already entered in the runtime's context. Callers treat
+    /// the result as a `&mut v8::HandleScope`.
+    pub fn handle_scope(&mut self) -> v8::HandleScope {
+        v8::HandleScope::with_context(&mut self.isolate, &self.context)
+    }
+
+    /// The shared state for a running isolate, reachable from any trampoline.
+    pub fn state(isolate: &v8::Isolate) -> Rc<RefCell<State>> {
+        isolate
+            .get_slot::<Rc<RefCell<State>>>()
+            .expect("runtime state missing from isolate slot")
+            .clone()
+    }
+
+    pub fn add_global(&mut self, name: &str, value: v8::Global<v8::Value>) {
+        let scope = &mut self.handle_scope();
+        let global = scope.get_current_context().global(scope);
+        let key = v8::String::new(scope, name).unwrap();
+        let value = v8::Local::new(scope, value);
+        global.set(scope, key.into(), value);
+    }
+
+    pub fn add_global_function(&mut self, name: &str, function: GlobalFunction) {
+        let scope = &mut self.handle_scope();
+        let global = scope.get_current_context().global(scope);
+        let key = v8::String::new(scope, name).unwrap();
+        let function = v8::Function::new(scope, function).unwrap();
+        global.set(scope, key.into(), function.into());
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<v8::Global<v8::Value>> {
+        let scope = &mut self.handle_scope();
+        let global = scope.get_current_context().global(scope);
+        let key = v8::String::new(scope, name).unwrap();
+        let value = global.get(scope, key.into())?;
+        Some(v8::Global::new(scope, value))
+    }
+
+    pub fn add_callback(&mut self, name: &str, callable: Zval) {
+        let state = JSRuntime::state(&self.isolate);
+        state.borrow_mut().callbacks.insert(name.to_string(), callable);
+    }
+
+    pub fn set_module_loader(&mut self, callable: Zval) {
+        let state = JSRuntime::state(&self.isolate);
+        state.borrow_mut().module_loader = Some(callable);
+    }
+
+    pub fn execute_string(
+        &mut self,
+        code: &str,
+        identifier: Option<String>,
+        _flags: Option<String>,
+        time_limit: Option<u64>,
+        memory_limit: Option<u64>,
+        await_promise: bool,
+    ) -> Result<Option<v8::Global<v8::Value>>, ExecutionError> {
+        let handle = self.isolate.thread_safe_handle();
+        let watchdog = Watchdog {
+            timed_out: Arc::new(AtomicBool::new(false)),
+            memory_exceeded: Arc::new(AtomicBool::new(false)),
+        };
+
+        // Fresh run: clear the cooperative-exit flag left by any prior script.
+        JSRuntime::state(&self.isolate).borrow_mut().exited = false;
+
+        let finished = Arc::new(AtomicBool::new(false));
+
+        // Terminate once actual heap usage reaches the requested byte
+        // ceiling. `near_heap_limit` fires against V8's own internal ceiling
+        // (derived from system memory, not the caller's value), so it can't
+        // be used to honor an arbitrary `memory_limit` -- a script can
+        // allocate well past it and never come close to V8's real default.
+        // Instead poll `get_heap_statistics()` from a background thread, the
+        // same way `time_limit` is enforced just below.
+        // `0`, like the original v8js extension, means "no limit".
+        let heap_poller = memory_limit.filter(|&limit| limit > 0).map(|limit| {
+            let handle = handle.clone();
+            let finished = finished.clone();
+            let memory_exceeded = watchdog.memory_exceeded.clone();
+            let isolate = IsolatePtr(&mut *self.isolate as *mut v8::Isolate);
+            std::thread::spawn(move || loop {
+                if finished.load(Ordering::SeqCst) {
+                    return;
+                }
+                // SAFETY: this thread only reads heap statistics while the
+                // run it's watching is still in flight (checked via
+                // `finished` right above) and stops polling as soon as
+                // `execute_string` tears the watchdogs down, so it never
+                // touches the isolate once the main thread is free to reuse
+                // it for the next run.
+                let used = unsafe {
+                    let isolate = &mut *isolate.0;
+                    let mut stats = v8::HeapStatistics::default();
+                    isolate.get_heap_statistics(&mut stats);
+                    stats.used_heap_size() as u64
+                };
+                if used >= limit {
+                    if !finished.load(Ordering::SeqCst) {
+                        memory_exceeded.store(true, Ordering::SeqCst);
+                        handle.terminate_execution();
+                    }
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            })
+        });
+
+        // Terminate after `time_limit` ms so a runaway script can't hang PHP.
+        // `0`, like the original v8js extension, means "no limit".
+        let timer = time_limit.filter(|&ms| ms > 0).map(|ms| {
+            let handle = handle.clone();
+            let finished = finished.clone();
+            let timed_out = watchdog.timed_out.clone();
+            std::thread::spawn(move || {
+                let mut elapsed = 0u64;
+                while elapsed < ms {
+                    if finished.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                    elapsed += 5;
+                }
+                if !finished.load(Ordering::SeqCst) {
+                    timed_out.store(true, Ordering::SeqCst);
+                    handle.terminate_execution();
+                }
+            })
+        });
+
+        let result = self.run_guarded(code, identifier, await_promise, &watchdog);
+
+        // Tear the watchdogs down before returning so they can't fire late
+        // into the next `execute_string`.
+        finished.store(true, Ordering::SeqCst);
+        if let Some(timer) = timer {
+            let _ = timer.join();
+        }
+        if let Some(heap_poller) = heap_poller {
+            let _ = heap_poller.join();
+        }
+
+        result
+    }
+
+    fn run_guarded(
+        &mut self,
+        code: &str,
+        identifier: Option<String>,
+        await_promise: bool,
+        watchdog: &Watchdog,
+    ) -> Result<Option<v8::Global<v8::Value>>, ExecutionError> {
+        let scope = &mut self.handle_scope();
+        let try_catch = &mut v8::TryCatch::new(scope);
+
+        let source = v8::String::new(try_catch, code).unwrap();
+        let resource = identifier.unwrap_or_else(|| String::from("V8Js::executeString()"));
+        let origin = script_origin(try_catch, resource.as_str());
+
+        let script = match v8::Script::compile(try_catch, source, Some(&origin)) {
+            Some(script) => script,
+            None => return terminate_result(try_catch, watchdog),
+        };
+
+        let result = match script.run(try_catch) {
+            Some(result) => result,
+            None => return terminate_result(try_catch, watchdog),
+        };
+
+        let result = if await_promise {
+            match await_result(try_catch, result, watchdog)? {
+                Some(result) => result,
+                None => return Ok(None),
+            }
+        } else {
+            result
+        };
+
+        Ok(Some(v8::Global::new(try_catch, result)))
+    }
+
+    /// Resolve a CommonJS `require(id)` relative to `parent` — the normalised
+    /// path of the requiring module, or `None` at the top level — returning the
+    /// module's `module.exports`. Repeat and circular requires hit the cache.
+    pub fn require(
+        scope: &mut v8::HandleScope,
+        identifier: &str,
+        parent: Option<&str>,
+    ) -> Option<v8::Global<v8::Value>> {
+        let normalised = normalise(identifier, parent);
+
+        // A module already being (or having been) resolved is served from the
+        // cache; the pre-cache below makes this the circular-dependency path.
+        let state = JSRuntime::state(scope);
+        if let Some(cached) = state.borrow().module_cache.get(&normalised) {
+            return Some(cached.clone());
+        }
+
+        // Fetch the source text via the PHP loader, dropping the state borrow
+        // first since the call may re-enter the isolate. An absent loader, a
+        // loader that throws, or a non-string result are all unresolvable
+        // modules — surface them as a JS exception rather than `undefined`.
+        let loader = match state.borrow().module_loader.as_ref() {
+            Some(loader) => loader.shallow_clone(),
+            None => {
+                return throw_require_error(
+                    scope,
+                    &format!("No module loader is set; cannot require '{}'", normalised),
+                )
+            }
+        };
+        let id = crate::PHPValue::String(normalised.clone());
+        let args: Vec<&dyn ext_php_rs::convert::IntoZvalDyn> = vec![&id];
+        let source = match loader.try_call(args) {
+            Ok(source) => source,
+            Err(_) => {
+                return throw_require_error(
+                    scope,
+                    &format!("Module loader threw while requiring '{}'", normalised),
+                )
+            }
+        };
+        let source = match source.string() {
+            Some(source) => source,
+            None => {
+                return throw_require_error(
+                    scope,
+                    &format!("Module loader for '{}' did not return source text", normalised),
+                )
+            }
+        };
+
+        // Wrap the module in the classic CommonJS envelope.
+        let wrapped = format!(
+            "(function (exports, module, require) {{ {} \n}})",
+            source
+        );
+        let code = v8::String::new(scope, wrapped.as_str())?;
+        let wrapper = v8::Script::compile(scope, code, None)?.run(scope)?;
+        let wrapper = v8::Local::<v8::Function>::try_from(wrapper).ok()?;
+
+        let exports = v8::Object::new(scope);
+        let module = v8::Object::new(scope);
+        let exports_key = v8::String::new(scope, "exports").unwrap();
+        module.set(scope, exports_key.into(), exports.into());
+
+        // Pre-cache the (initially empty) exports so a circular require sees the
+        // partially-populated object rather than recursing forever.
+        let exports_value: v8::Local<v8::Value> = exports.into();
+        state
+            .borrow_mut()
+            .module_cache
+            .insert(normalised.clone(), v8::Global::new(scope, exports_value));
+
+        // A `require` bound to this module's path so its own relative requires
+        // resolve against the right directory.
+        let parent_data = v8::String::new(scope, normalised.as_str()).unwrap();
+        let scoped_require = v8::FunctionBuilder::<v8::Function>::new(scoped_require_callback)
+            .data(parent_data.into())
+            .build(scope)
+            .unwrap();
+
+        let recv = v8::undefined(scope).into();
+        let call_args = [exports.into(), module.into(), scoped_require.into()];
+        wrapper.call(scope, recv, &call_args)?;
+
+        // The body may have reassigned `module.exports`; re-read and cache it.
+        let final_exports = module.get(scope, exports_key.into())?;
+        let global = v8::Global::new(scope, final_exports);
+        state
+            .borrow_mut()
+            .module_cache
+            .insert(normalised, global.clone());
+        Some(global)
+    }
+
+    pub fn create_snapshot(source: String) -> Option<Vec<u8>> {
+        PLATFORM.call_once(|| {
+            let platform = v8::new_default_platform(0, false).make_shared();
+            v8::V8::initialize_platform(platform);
+            v8::V8::initialize();
+        });
+
+        let mut creator = v8::SnapshotCreator::new(None);
+        // SAFETY: the isolate is owned by the creator for the duration of the
+        // snapshot and is not used after `create_blob` consumes it.
+        let isolate = unsafe { creator.get_owned_isolate() };
+        let mut isolate = isolate;
+        {
+            let scope = &mut v8::HandleScope::new(&mut isolate);
+            let context = v8::Context::new(scope);
+            creator.set_default_context(context);
+            let scope = &mut v8::ContextScope::new(scope, context);
+            let code = v8::String::new(scope, source.as_str())?;
+            let script = v8::Script::compile(scope, code, None)?;
+            script.run(scope)?;
+        }
+        std::mem::forget(isolate);
+        let blob = creator.create_blob(v8::FunctionCodeHandling::Keep)?;
+        Some(blob.to_vec())
+    }
+}
+
+/// Normalise a require identifier. Relative ids (`./`, `../`) are resolved
+/// against the directory of the requiring module by popping the module's file
+/// name, dropping `.` segments and popping one entry per `..`; bare names are
+/// handed to the loader verbatim.
+fn normalise(identifier: &str, parent: Option<&str>) -> String {
+    if !(identifier.starts_with("./") || identifier.starts_with("../")) {
+        return identifier.to_string();
+    }
+
+    let mut stack: Vec<String> = match parent {
+        Some(parent) => {
+            let mut parts: Vec<String> = parent.split('/').map(String::from).collect();
+            parts.pop(); // drop the parent's file name, keep its directory
+            parts
+        }
+        None => Vec::new(),
+    };
+
+    for segment in identifier.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => {
+                stack.pop();
+            }
+            other => stack.push(other.to_string()),
+        }
+    }
+    stack.join("/")
+}
+
+/// The per-module `require` trampoline; its bound data is the normalised path
+/// of the module that owns this `require`, used as the resolution base.
+fn scoped_require_callback(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let parent = args.data().unwrap().to_rust_string_lossy(scope);
+    let identifier = args.get(0).to_rust_string_lossy(scope);
+    if let Some(exports) = JSRuntime::require(scope, identifier.as_str(), Some(parent.as_str())) {
+        rv.set(v8::Local::new(scope, exports));
+    }
+}
+
+/// Build a `ScriptOrigin` tagging a chunk of source with a resource name so
+/// stack traces and `V8JsScriptException` carry a meaningful location.
+fn script_origin<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    resource: &str,
+) -> v8::ScriptOrigin<'a> {
+    let resource = v8::String::new(scope, resource).unwrap();
+    v8::ScriptOrigin::new(
+        scope,
+        resource.into(),
+        0,
+        0,
+        false,
+        0,
+        v8::undefined(scope).into(),
+        false,
+        false,
+        false,
+    )
+}
+
+/// Safety net bounding the microtask pump when no time limit is in force, so a
+/// never-settling promise cannot spin the PHP process forever.
+const MAX_DRAIN_ITERATIONS: u64 = 10_000_000;
+
+/// Drain the microtask queue until a top-level promise settles, then hand back
+/// its fulfilled value (or raise its rejection as a caught error). Non-promise
+/// results pass straight through.
+fn await_result<'s>(
+    scope: &mut v8::TryCatch<'s, v8::HandleScope>,
+    result: v8::Local<'s, v8::Value>,
+    watchdog: &Watchdog,
+) -> Result<Option<v8::Local<'s, v8::Value>>, ExecutionError> {
+    let promise = match v8::Local::<v8::Promise>::try_from(result) {
+        Ok(promise) => promise,
+        Err(_) => return Ok(Some(result)),
+    };
+
+    let mut drained = 0u64;
+    while promise.state() == v8::PromiseState::Pending {
+        scope.perform_microtask_checkpoint();
+        // A watchdog termination breaks the pump so a never-settling promise
+        // cannot hang the PHP process.
+        if scope.is_execution_terminating() {
+            // Propagates the watchdog error, or falls through to a clean end if
+            // the termination was a cooperative `exit()`.
+            terminate_result(scope, watchdog)?;
+            return Ok(None);
+        }
+        // Independent of the time watchdog: if none was set, this guarantees a
+        // never-settling promise still terminates instead of hanging.
+        drained += 1;
+        if drained >= MAX_DRAIN_ITERATIONS {
+            return Err(ExecutionError::TimeLimit);
+        }
+    }
+
+    match promise.state() {
+        v8::PromiseState::Fulfilled => Ok(Some(promise.result(scope))),
+        v8::PromiseState::Rejected => {
+            let reason = promise.result(scope);
+            Err(ExecutionError::Script(JSError::from_value(scope, reason)))
+        }
+        v8::PromiseState::Pending => Ok(None),
+    }
+}
+
+/// Flags shared with the time and memory watchdogs so a terminated run can be
+/// attributed to the right cause.
+struct Watchdog {
+    timed_out: Arc<AtomicBool>,
+    memory_exceeded: Arc<AtomicBool>,
+}
+
+/// Throw a JS `Error` describing an unresolvable `require`, returning `None` so
+/// callers leave the pending exception to propagate.
+fn throw_require_error(
+    scope: &mut v8::HandleScope,
+    message: &str,
+) -> Option<v8::Global<v8::Value>> {
+    let message = v8::String::new(scope, message).unwrap();
+    let error = v8::Exception::error(scope, message);
+    scope.throw_exception(error);
+    None
+}
+
+/// Raw isolate pointer handed to the heap-polling thread in `execute_string`.
+/// Not `Send` by default since V8 isolates aren't generally thread-safe, but
+/// the poller only ever reads heap statistics while the run it watches is
+/// still in flight on the main thread, and stops before `execute_string`
+/// returns -- see the `SAFETY` comment at its only call site.
+struct IsolatePtr(*mut v8::Isolate);
+unsafe impl Send for IsolatePtr {}
+
+/// Turn a failed/terminated run into a result. A cooperative `exit()` ends the
+/// run cleanly (`Ok(None)`); anything else is classified as an error.
+fn terminate_result(
+    try_catch: &mut v8::TryCatch<v8::HandleScope>,
+    watchdog: &Watchdog,
+) -> Result<Option<v8::Global<v8::Value>>, ExecutionError> {
+    let exited = {
+        let isolate: &mut v8::Isolate = try_catch.as_mut();
+        JSRuntime::state(isolate).borrow().exited
+    };
+    if exited {
+        // Clear V8's terminate state so the isolate can run again afterwards.
+        try_catch.cancel_terminate_execution();
+        return Ok(None);
+    }
+    Err(classify(try_catch, watchdog))
+}
+
+/// Map a terminated-or-thrown `TryCatch` onto an [`ExecutionError`], consulting
+/// the watchdog flags rather than relying on `has_terminated()` alone (which is
+/// still false when a time limit is hit mid microtask-drain).
+fn classify(
+    try_catch: &mut v8::TryCatch<v8::HandleScope>,
+    watchdog: &Watchdog,
+) -> ExecutionError {
+    if watchdog.memory_exceeded.load(Ordering::SeqCst) {
+        return ExecutionError::MemoryLimit;
+    }
+    if watchdog.timed_out.load(Ordering::SeqCst) {
+        return ExecutionError::TimeLimit;
+    }
+    if try_catch.has_terminated() || try_catch.is_execution_terminating() {
+        // Terminated without a watchdog cause recorded; treat as a timeout.
+        return ExecutionError::TimeLimit;
+    }
+    match try_catch.exception() {
+        Some(exception) => ExecutionError::Script(JSError::from_try_catch(try_catch, exception)),
+        None => ExecutionError::Script(JSError {
+            message: String::from("Unknown JavaScript error"),
+            line: 0,
+            source_line: String::new(),
+            trace: String::new(),
+        }),
+    }
+}